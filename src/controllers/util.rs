@@ -1,6 +1,9 @@
 use super::prelude::*;
 
+use std::sync::Arc;
+
 use crate::middleware::current_user::TrustedUserId;
+use crate::middleware::cors::CorsConfig;
 use crate::middleware::log_request;
 use crate::models::{ApiToken, User};
 use crate::util::errors::{
@@ -54,10 +57,22 @@ fn verify_origin(req: &dyn RequestExt) -> AppResult<()> {
         },
     };
 
-    let bad_origin = headers
-        .get_all(header::ORIGIN)
-        .iter()
-        .find(|h| h.to_str().unwrap_or_default() != expected_origin);
+    // Besides the single computed `expected_origin`, operators can configure a
+    // broader allowlist (e.g. other trusted front-ends) via `CorsConfig`. The
+    // same config backs the `Access-Control-*` response headers set by
+    // `CorsMiddleware`, so there's only one place that defines "who we trust".
+    let cors_config = req.extensions().find::<Arc<CorsConfig>>();
+
+    let bad_origin = headers.get_all(header::ORIGIN).iter().find(|h| {
+        let origin = h.to_str().unwrap_or_default();
+        if origin == expected_origin {
+            return false;
+        }
+        match cors_config {
+            Some(config) => !config.origin_allowed(origin),
+            None => true,
+        }
+    });
     if let Some(bad_origin) = bad_origin {
         let error_message = format!(
             "only same-origin requests can be authenticated. expected {}, got {:?}",
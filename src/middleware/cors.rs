@@ -0,0 +1,597 @@
+use super::prelude::*;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+use conduit::{Headers, Method};
+use regex::Regex;
+
+use crate::util::errors::AppError;
+
+/// A single entry in a `CorsConfig` allowlist: either an exact origin, or a
+/// regular expression matched against the `Origin` header (e.g. to allow
+/// `https://*.crates.io` preview deploys without enumerating every
+/// subdomain).
+///
+/// `OriginPattern::regex` anchors the pattern so it must match the whole
+/// header; a `Regex` variant constructed directly is matched as given, so an
+/// unanchored regex there matches any substring of the header.
+///
+/// Regexes are compiled once, when the `OriginPattern` is built, rather than
+/// on every request.
+#[derive(Debug, Clone)]
+pub enum OriginPattern {
+    Literal(String),
+    Regex(Regex),
+}
+
+impl OriginPattern {
+    /// Compiles `pattern` into a regex `OriginPattern`, anchoring it so a
+    /// match must cover the whole `Origin` header rather than a substring.
+    pub fn regex(pattern: &str) -> Result<OriginPattern, regex::Error> {
+        Regex::new(&format!("^(?:{})$", pattern)).map(OriginPattern::Regex)
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            OriginPattern::Literal(expected) => expected == origin,
+            OriginPattern::Regex(re) => re.is_match(origin),
+        }
+    }
+}
+
+impl From<String> for OriginPattern {
+    fn from(origin: String) -> Self {
+        OriginPattern::Literal(origin)
+    }
+}
+
+impl<'a> From<&'a str> for OriginPattern {
+    fn from(origin: &'a str) -> Self {
+        OriginPattern::Literal(origin.to_string())
+    }
+}
+
+/// Configuration describing which cross-origin requests the API should accept.
+///
+/// A single `CorsConfig` is shared (via `Arc`) between the `CorsMiddleware` and
+/// the authentication gate in `controllers::util`, so the set of trusted
+/// front-ends only has to be defined once.
+#[derive(Debug, Clone)]
+pub struct CorsConfig {
+    pub allowed_origins: Vec<OriginPattern>,
+    pub allowed_methods: HashSet<String>,
+    pub allowed_headers: HashSet<String>,
+    pub exposed_headers: HashSet<String>,
+    pub credentials: bool,
+    pub max_age: Option<u32>,
+}
+
+impl CorsConfig {
+    /// Returns true if `origin` matches any literal or regex entry in the
+    /// configured allowlist.
+    pub fn origin_allowed(&self, origin: &str) -> bool {
+        self.allowed_origins.iter().any(|pattern| pattern.matches(origin))
+    }
+}
+
+/// Marker inserted into the request extensions by `CorsMiddleware::before` once
+/// an `Origin` header has matched the allowlist, so `after` knows which origin
+/// to echo back without re-parsing the request.
+struct MatchedOrigin(String);
+
+/// A pre-rendered CORS preflight response.
+///
+/// `before` can only short-circuit the middleware chain by returning an
+/// `Err`, and a failed `before` skips this middleware's own `after` (see
+/// `conduit_middleware`'s dispatch loop), so the preflight response has to be
+/// fully built here rather than assembled later. `AppError::response` is what
+/// turns this "error" into the actual `204` the browser sees.
+#[derive(Debug)]
+struct Preflight {
+    headers: Vec<(String, String)>,
+}
+
+impl fmt::Display for Preflight {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CORS preflight response")
+    }
+}
+
+impl AppError for Preflight {
+    fn response(&self) -> Option<Response> {
+        let mut headers = HashMap::new();
+        for (name, value) in &self.headers {
+            headers.insert(name.clone(), vec![value.clone()]);
+        }
+        Some(Response {
+            status: (204, "No Content"),
+            headers,
+            body: Box::new(io::empty()),
+        })
+    }
+}
+
+/// Middleware that enforces and annotates cross-origin requests.
+///
+/// Unlike the previous hard-coded same-origin check, `CorsMiddleware` is built
+/// from a `CorsConfig` allowlist and is responsible for both telling the
+/// browser which origins, methods and headers are acceptable (via
+/// `Access-Control-*` response headers) and for sharing that allowlist with
+/// `controllers::util::verify_origin` so authentication isn't limited to the
+/// single computed deployment origin.
+#[allow(missing_debug_implementations)]
+pub struct CorsMiddleware {
+    config: Arc<CorsConfig>,
+}
+
+impl CorsMiddleware {
+    pub fn new(config: CorsConfig) -> CorsMiddleware {
+        CorsMiddleware {
+            config: Arc::new(config),
+        }
+    }
+
+    /// Builds the `Access-Control-*` headers for a preflight response,
+    /// omitting the allow headers entirely when `origin` isn't on the
+    /// allowlist so the browser's own CORS check rejects the call.
+    fn preflight_headers(&self, req: &Request, origin: &str) -> Vec<(String, String)> {
+        if !self.config.origin_allowed(origin) {
+            return Vec::new();
+        }
+
+        // Sorted for a stable, diffable header value -- `allowed_methods` is a
+        // `HashSet` and its iteration order is otherwise nondeterministic.
+        let mut allowed_methods: Vec<&str> = self
+            .config
+            .allowed_methods
+            .iter()
+            .map(String::as_str)
+            .collect();
+        allowed_methods.sort_unstable();
+
+        let mut headers = vec![
+            ("Access-Control-Allow-Origin".to_string(), origin.to_string()),
+            (
+                "Access-Control-Allow-Methods".to_string(),
+                allowed_methods.join(", "),
+            ),
+        ];
+
+        // The browser's CORS check on the preflight response itself requires
+        // this header when the real request will be sent with credentials,
+        // or it discards the preflight and never sends the real request.
+        if self.config.credentials {
+            headers.push((
+                "Access-Control-Allow-Credentials".to_string(),
+                "true".to_string(),
+            ));
+        }
+
+        let requested_headers = req
+            .headers()
+            .find("Access-Control-Request-Headers")
+            .and_then(|values| values.into_iter().next())
+            .unwrap_or_default();
+        headers.push((
+            "Access-Control-Allow-Headers".to_string(),
+            filter_allowed_headers(&self.config.allowed_headers, requested_headers),
+        ));
+
+        if let Some(max_age) = self.config.max_age {
+            headers.push(("Access-Control-Max-Age".to_string(), max_age.to_string()));
+        }
+
+        // Without this, a cache sitting in front of the API could serve a
+        // preflight response cached for one allowed origin back to a browser
+        // from a different allowed origin.
+        headers.push(("Vary".to_string(), "Origin".to_string()));
+
+        headers
+    }
+
+    /// Builds the `Access-Control-*` headers for a successful or error
+    /// response once an `Origin` has matched the allowlist.
+    fn cors_response_headers(&self, origin: String) -> Vec<(String, String)> {
+        let allow_origin = if self.config.credentials {
+            origin
+        } else {
+            "*".to_string()
+        };
+        let mut headers = vec![("Access-Control-Allow-Origin".to_string(), allow_origin)];
+
+        if self.config.credentials {
+            headers.push((
+                "Access-Control-Allow-Credentials".to_string(),
+                "true".to_string(),
+            ));
+        }
+
+        if !self.config.exposed_headers.is_empty() {
+            let exposed = self
+                .config
+                .exposed_headers
+                .iter()
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(", ");
+            headers.push(("Access-Control-Expose-Headers".to_string(), exposed));
+        }
+
+        headers.push(("Vary".to_string(), "Origin".to_string()));
+
+        headers
+    }
+
+    fn apply_cors_headers(&self, res: &mut Response, origin: String) {
+        merge_headers(&mut res.headers, &self.cors_response_headers(origin));
+    }
+}
+
+/// Filters a comma-separated `Access-Control-Request-Headers` value down to
+/// the entries present in `allowed` (case-insensitively), dropping anything
+/// else so it's never echoed back to the browser.
+fn filter_allowed_headers(allowed: &HashSet<String>, requested: &str) -> String {
+    requested
+        .split(',')
+        .map(|h| h.trim())
+        .filter(|h| !h.is_empty())
+        .filter(|h| allowed.iter().any(|a| a.eq_ignore_ascii_case(h)))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl Middleware for CorsMiddleware {
+    fn before(&self, req: &mut Request) -> Result<(), Box<Error + Send>> {
+        let origin = req
+            .headers()
+            .find("Origin")
+            .and_then(|values| values.into_iter().next())
+            .map(|origin| origin.to_string());
+
+        let is_preflight = req.method() == Method::Options
+            && req.headers().has("Access-Control-Request-Method");
+
+        if is_preflight {
+            // A preflight short-circuits via `Err` before `after` ever runs
+            // (see `Preflight`'s doc comment), so nothing would pop this
+            // extension again -- don't insert it on this path.
+            let headers = origin
+                .as_ref()
+                .map(|origin| self.preflight_headers(req, origin))
+                .unwrap_or_default();
+            return Err(Box::new(Preflight { headers }));
+        }
+
+        req.mut_extensions().insert(Arc::clone(&self.config));
+
+        if let Some(origin) = origin {
+            if self.config.origin_allowed(&origin) {
+                req.mut_extensions().insert(MatchedOrigin(origin));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn after(
+        &self,
+        req: &mut Request,
+        res: Result<Response, Box<Error + Send>>,
+    ) -> Result<Response, Box<Error + Send>> {
+        req.mut_extensions().pop::<Arc<CorsConfig>>();
+        let matched = req.mut_extensions().pop::<MatchedOrigin>();
+
+        // Most 401/403/404/422/500 responses in this codebase are represented
+        // as `Err` until some later boundary calls `AppError::response()` (see
+        // `verify_origin`'s `forbidden()`/`internal()` errors). A trusted
+        // frontend needs the CORS headers on those just as much as on a
+        // successful response, or the browser reports an opaque CORS failure
+        // instead of the real status/body.
+        let origin = match matched {
+            Some(MatchedOrigin(origin)) => origin,
+            None => return res,
+        };
+
+        match res {
+            Ok(mut res) => {
+                self.apply_cors_headers(&mut res, origin);
+                Ok(res)
+            }
+            Err(err) => {
+                let headers = self.cors_response_headers(origin);
+                Err(Box::new(RenderedError { err, headers }))
+            }
+        }
+    }
+}
+
+/// Wraps an error so its eventual `AppError::response()` gets the CORS
+/// headers merged in, while staying a thin pass-through otherwise.
+///
+/// This re-derives the response from the original error on every call to
+/// `response()` (rather than rendering and caching a `Response` up front),
+/// matching `Preflight::response()`'s "rebuild fresh each time" contract so a
+/// renderer that calls `response()` more than once doesn't get `None` the
+/// second time. `Display`/`Debug` also forward to the original error, so
+/// logging based on the message still sees the real error, though anything
+/// downcasting to the original error's concrete type will see `RenderedError`
+/// instead.
+struct RenderedError {
+    err: Box<Error + Send>,
+    headers: Vec<(String, String)>,
+}
+
+impl fmt::Debug for RenderedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.err, f)
+    }
+}
+
+impl fmt::Display for RenderedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.err, f)
+    }
+}
+
+impl AppError for RenderedError {
+    fn response(&self) -> Option<Response> {
+        let mut res = self.err.response()?;
+        merge_headers(&mut res.headers, &self.headers);
+        Some(res)
+    }
+}
+
+/// Merges `extra` into `target`, appending to (rather than overwriting) an
+/// existing `Vary` header so multiple middlewares can each contribute a value.
+fn merge_headers(target: &mut HashMap<String, Vec<String>>, extra: &[(String, String)]) {
+    for (name, value) in extra {
+        if name == "Vary" {
+            target.entry(name.clone()).or_insert_with(Vec::new).push(value.clone());
+        } else {
+            target.insert(name.clone(), vec![value.clone()]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use conduit_test::MockRequest;
+
+    fn ok_response() -> Response {
+        Response {
+            status: (200, "OK"),
+            headers: HashMap::new(),
+            body: Box::new(io::empty()),
+        }
+    }
+
+    fn config(allowed_origins: Vec<OriginPattern>) -> CorsConfig {
+        CorsConfig {
+            allowed_origins,
+            allowed_methods: HashSet::new(),
+            allowed_headers: HashSet::new(),
+            exposed_headers: HashSet::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+
+    #[test]
+    fn origin_allowed_matches_literal_entries_exactly() {
+        let config = config(vec![OriginPattern::from("https://crates.io")]);
+
+        assert!(config.origin_allowed("https://crates.io"));
+        assert!(!config.origin_allowed("https://evil.example.com"));
+        assert!(!config.origin_allowed("https://crates.io.evil.com"));
+    }
+
+    #[test]
+    fn filter_allowed_headers_is_case_insensitive_and_drops_unknown_headers() {
+        let mut allowed = HashSet::new();
+        allowed.insert("X-Api-Key".to_string());
+        allowed.insert("Content-Type".to_string());
+
+        let filtered = filter_allowed_headers(&allowed, "x-api-key, X-Evil-Header, content-type");
+
+        assert_eq!(filtered, "x-api-key, content-type");
+    }
+
+    #[test]
+    fn filter_allowed_headers_drops_everything_when_nothing_matches() {
+        let allowed = HashSet::new();
+
+        assert_eq!(filter_allowed_headers(&allowed, "x-api-key"), "");
+    }
+
+    #[test]
+    fn origin_allowed_matches_regex_entries() {
+        let config = config(vec![
+            OriginPattern::regex(r"https://[a-z0-9-]+\.crates\.io").unwrap(),
+        ]);
+
+        assert!(config.origin_allowed("https://preview-123.crates.io"));
+        assert!(!config.origin_allowed("https://crates.io"));
+    }
+
+    #[test]
+    fn origin_allowed_regex_entries_are_anchored() {
+        let config = config(vec![
+            OriginPattern::regex(r"https://[a-z0-9-]+\.crates\.io").unwrap(),
+        ]);
+
+        // Without anchoring, an attacker could wrap a trusted-looking origin
+        // in extra characters and still have the pattern match as a substring.
+        assert!(!config.origin_allowed("evil-https://preview-123.crates.io"));
+        assert!(!config.origin_allowed("https://preview-123.crates.io.evil.com"));
+    }
+
+    #[derive(Debug)]
+    struct StubError;
+
+    impl fmt::Display for StubError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "stub error")
+        }
+    }
+
+    impl AppError for StubError {
+        fn response(&self) -> Option<Response> {
+            Some(Response {
+                status: (403, "Forbidden"),
+                headers: HashMap::new(),
+                body: Box::new(io::empty()),
+            })
+        }
+    }
+
+    #[test]
+    fn rendered_error_merges_cors_headers_into_the_wrapped_errors_response() {
+        let wrapped = RenderedError {
+            err: Box::new(StubError),
+            headers: vec![("Access-Control-Allow-Origin".to_string(), "https://crates.io".to_string())],
+        };
+
+        let res = wrapped.response().unwrap();
+
+        assert_eq!(res.status.0, 403);
+        assert_eq!(
+            res.headers.get("Access-Control-Allow-Origin"),
+            Some(&vec!["https://crates.io".to_string()])
+        );
+    }
+
+    #[test]
+    fn rendered_error_response_is_idempotent() {
+        let wrapped = RenderedError {
+            err: Box::new(StubError),
+            headers: Vec::new(),
+        };
+
+        assert!(wrapped.response().is_some());
+        assert!(wrapped.response().is_some());
+    }
+
+    #[test]
+    fn merge_headers_appends_to_an_existing_vary_header_instead_of_overwriting_it() {
+        let mut headers = HashMap::new();
+        headers.insert("Vary".to_string(), vec!["Accept-Encoding".to_string()]);
+
+        merge_headers(&mut headers, &[("Vary".to_string(), "Origin".to_string())]);
+
+        assert_eq!(
+            headers.get("Vary"),
+            Some(&vec!["Accept-Encoding".to_string(), "Origin".to_string()])
+        );
+    }
+
+    #[test]
+    fn before_short_circuits_a_preflight_request_with_cors_headers() {
+        let mut cfg = config(vec![OriginPattern::from("https://crates.io")]);
+        cfg.allowed_methods.insert("GET".to_string());
+        let middleware = CorsMiddleware::new(cfg);
+
+        let mut req = MockRequest::new(Method::Options, "/");
+        req.header("Origin", "https://crates.io");
+        req.header("Access-Control-Request-Method", "GET");
+
+        let err = middleware.before(&mut req).unwrap_err();
+        let res = err.response().unwrap();
+
+        assert_eq!(res.status.0, 204);
+        assert_eq!(
+            res.headers.get("Access-Control-Allow-Origin"),
+            Some(&vec!["https://crates.io".to_string()])
+        );
+        assert_eq!(res.headers.get("Vary"), Some(&vec!["Origin".to_string()]));
+    }
+
+    #[test]
+    fn before_does_not_leave_the_cors_config_extension_attached_on_a_preflight_request() {
+        let cfg = config(vec![OriginPattern::from("https://crates.io")]);
+        let middleware = CorsMiddleware::new(cfg);
+
+        let mut req = MockRequest::new(Method::Options, "/");
+        req.header("Origin", "https://crates.io");
+        req.header("Access-Control-Request-Method", "GET");
+
+        let _ = middleware.before(&mut req);
+
+        assert!(req.mut_extensions().find::<Arc<CorsConfig>>().is_none());
+    }
+
+    #[test]
+    fn before_short_circuits_a_preflight_request_from_a_disallowed_origin_with_no_allow_headers() {
+        let mut cfg = config(vec![OriginPattern::from("https://crates.io")]);
+        cfg.allowed_methods.insert("GET".to_string());
+        let middleware = CorsMiddleware::new(cfg);
+
+        let mut req = MockRequest::new(Method::Options, "/");
+        req.header("Origin", "https://untrusted.example.com");
+        req.header("Access-Control-Request-Method", "GET");
+
+        let err = middleware.before(&mut req).unwrap_err();
+        let res = err.response().unwrap();
+
+        assert_eq!(res.status.0, 204);
+        assert!(res
+            .headers
+            .keys()
+            .all(|name| !name.starts_with("Access-Control-")));
+    }
+
+    #[test]
+    fn before_short_circuits_a_preflight_request_with_no_origin_header_with_no_allow_headers() {
+        let mut cfg = config(vec![OriginPattern::from("https://crates.io")]);
+        cfg.allowed_methods.insert("GET".to_string());
+        let middleware = CorsMiddleware::new(cfg);
+
+        let mut req = MockRequest::new(Method::Options, "/");
+        req.header("Access-Control-Request-Method", "GET");
+
+        let err = middleware.before(&mut req).unwrap_err();
+        let res = err.response().unwrap();
+
+        assert_eq!(res.status.0, 204);
+        assert!(res
+            .headers
+            .keys()
+            .all(|name| !name.starts_with("Access-Control-")));
+    }
+
+    #[test]
+    fn before_then_after_adds_cors_headers_to_a_matched_non_preflight_request() {
+        let cfg = config(vec![OriginPattern::from("https://crates.io")]);
+        let middleware = CorsMiddleware::new(cfg);
+
+        let mut req = MockRequest::new(Method::Get, "/");
+        req.header("Origin", "https://crates.io");
+
+        middleware.before(&mut req).unwrap();
+        let res = middleware.after(&mut req, Ok(ok_response())).unwrap();
+
+        assert_eq!(
+            res.headers.get("Access-Control-Allow-Origin"),
+            Some(&vec!["*".to_string()])
+        );
+        assert!(req.mut_extensions().find::<Arc<CorsConfig>>().is_none());
+    }
+
+    #[test]
+    fn after_leaves_an_unmatched_requests_response_untouched() {
+        let cfg = config(vec![OriginPattern::from("https://crates.io")]);
+        let middleware = CorsMiddleware::new(cfg);
+
+        let mut req = MockRequest::new(Method::Get, "/");
+        req.header("Origin", "https://untrusted.example.com");
+
+        middleware.before(&mut req).unwrap();
+        let res = middleware.after(&mut req, Ok(ok_response())).unwrap();
+
+        assert!(res.headers.get("Access-Control-Allow-Origin").is_none());
+    }
+}
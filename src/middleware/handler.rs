@@ -0,0 +1,202 @@
+use super::prelude::*;
+
+/// A composable, onion-style request handler.
+///
+/// Unlike `Middleware`'s separate `before`/`after` hooks, `Handler::call`
+/// receives the rest of the pipeline as `next` and decides when (and
+/// whether) to invoke it, so a handler can set up and tear down
+/// request-scoped state in a single lexical scope -- e.g. timing a request,
+/// or rolling back a per-request DB transaction when the inner call returns
+/// an error.
+pub trait Handler: Send + Sync + 'static {
+    fn call(&self, req: &mut Request, next: Next) -> Result<Response, Box<Error + Send>>;
+}
+
+/// The remaining handlers in the chain, plus the terminal app handler that
+/// runs once every `Handler` has had a chance to wrap the request.
+pub struct Next<'a> {
+    handlers: &'a mut [Box<dyn Handler>],
+    app: &'a dyn conduit::Handler,
+}
+
+impl<'a> Next<'a> {
+    pub fn new(handlers: &'a mut [Box<dyn Handler>], app: &'a dyn conduit::Handler) -> Next<'a> {
+        Next { handlers, app }
+    }
+
+    /// Runs the next handler in the chain, falling through to the terminal
+    /// app handler once the chain is exhausted.
+    pub fn run(self, req: &mut Request) -> Result<Response, Box<Error + Send>> {
+        match self.handlers.split_first_mut() {
+            Some((head, tail)) => head.call(
+                req,
+                Next {
+                    handlers: tail,
+                    app: self.app,
+                },
+            ),
+            None => self.app.call(req),
+        }
+    }
+}
+
+/// Adapts an existing `Middleware` (`before`/`after` hooks) into a `Handler`,
+/// so `AppMiddleware`, `CorsMiddleware` and other existing implementors can
+/// keep working unchanged while new code is written directly against
+/// `Handler`.
+pub struct FromMiddleware<M>(pub M);
+
+impl<M: Middleware> Handler for FromMiddleware<M> {
+    fn call(&self, req: &mut Request, next: Next) -> Result<Response, Box<Error + Send>> {
+        self.0.before(req)?;
+        let res = next.run(req);
+        self.0.after(req, res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::fmt;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    use conduit::Method;
+    use conduit_test::MockRequest;
+
+    use crate::util::errors::AppError;
+
+    fn ok_response() -> Response {
+        Response {
+            status: (200, "OK"),
+            headers: HashMap::new(),
+            body: Box::new(io::empty()),
+        }
+    }
+
+    struct TerminalApp;
+
+    impl conduit::Handler for TerminalApp {
+        fn call(&self, _req: &mut dyn conduit::Request) -> Result<Response, Box<Error + Send>> {
+            Ok(ok_response())
+        }
+    }
+
+    struct RecordingHandler {
+        name: &'static str,
+        log: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    impl Handler for RecordingHandler {
+        fn call(&self, req: &mut Request, next: Next) -> Result<Response, Box<Error + Send>> {
+            self.log.lock().unwrap().push(self.name);
+            next.run(req)
+        }
+    }
+
+    #[derive(Debug)]
+    struct TestError;
+
+    impl fmt::Display for TestError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "boom")
+        }
+    }
+
+    impl AppError for TestError {
+        fn response(&self) -> Option<Response> {
+            Some(Response {
+                status: (500, "Internal Server Error"),
+                headers: HashMap::new(),
+                body: Box::new(io::empty()),
+            })
+        }
+    }
+
+    struct FailingHandler;
+
+    impl Handler for FailingHandler {
+        fn call(&self, _req: &mut Request, _next: Next) -> Result<Response, Box<Error + Send>> {
+            Err(Box::new(TestError))
+        }
+    }
+
+    #[test]
+    fn next_run_invokes_handlers_in_order_then_the_terminal_app() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut handlers: Vec<Box<dyn Handler>> = vec![
+            Box::new(RecordingHandler {
+                name: "first",
+                log: Arc::clone(&log),
+            }),
+            Box::new(RecordingHandler {
+                name: "second",
+                log: Arc::clone(&log),
+            }),
+        ];
+        let app = TerminalApp;
+        let mut req = MockRequest::new(Method::Get, "/");
+
+        let res = Next::new(&mut handlers, &app).run(&mut req).unwrap();
+
+        assert_eq!(res.status.0, 200);
+        assert_eq!(*log.lock().unwrap(), vec!["first", "second"]);
+    }
+
+    #[test]
+    fn next_run_skips_remaining_handlers_once_one_returns_an_error() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut handlers: Vec<Box<dyn Handler>> = vec![
+            Box::new(FailingHandler),
+            Box::new(RecordingHandler {
+                name: "never reached",
+                log: Arc::clone(&log),
+            }),
+        ];
+        let app = TerminalApp;
+        let mut req = MockRequest::new(Method::Get, "/");
+
+        let err = Next::new(&mut handlers, &app).run(&mut req).unwrap_err();
+
+        assert!(log.lock().unwrap().is_empty());
+        assert_eq!(err.response().unwrap().status.0, 500);
+    }
+
+    #[test]
+    fn from_middleware_adapts_an_existing_middleware_into_a_handler() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let middleware = FromMiddleware(RecordingMiddleware {
+            log: Arc::clone(&log),
+        });
+        let mut handlers: Vec<Box<dyn Handler>> = vec![Box::new(middleware)];
+        let app = TerminalApp;
+        let mut req = MockRequest::new(Method::Get, "/");
+
+        let res = Next::new(&mut handlers, &app).run(&mut req).unwrap();
+
+        assert_eq!(res.status.0, 200);
+        assert_eq!(*log.lock().unwrap(), vec!["before", "after"]);
+    }
+
+    struct RecordingMiddleware {
+        log: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    impl Middleware for RecordingMiddleware {
+        fn before(&self, _req: &mut Request) -> Result<(), Box<Error + Send>> {
+            self.log.lock().unwrap().push("before");
+            Ok(())
+        }
+
+        fn after(
+            &self,
+            _req: &mut Request,
+            res: Result<Response, Box<Error + Send>>,
+        ) -> Result<Response, Box<Error + Send>> {
+            self.log.lock().unwrap().push("after");
+            res
+        }
+    }
+}